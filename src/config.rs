@@ -0,0 +1,70 @@
+use std::env;
+use std::error::Error as ErrorTrait;
+use std::fmt;
+use std::num::ParseIntError;
+
+const DEFAULT_BIND: &str = "0.0.0.0";
+const DEFAULT_PORT: u16 = 6070;
+const DEFAULT_REDIS_URL: &str = "redis://127.0.0.1:6379";
+
+/// Something is wrong with the environment the service was started with.
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    InvalidPort(ParseIntError),
+    InvalidRedisUrl(redis::RedisError),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::InvalidPort(ref e) => write!(f, "invalid PORT: {}", e),
+            ConfigError::InvalidRedisUrl(ref e) => write!(f, "invalid REDIS_URL: {}", e),
+        }
+    }
+}
+
+impl ErrorTrait for ConfigError {
+    fn source(&self) -> Option<&(dyn ErrorTrait + 'static)> {
+        match *self {
+            ConfigError::InvalidPort(ref e) => Some(e),
+            ConfigError::InvalidRedisUrl(ref e) => Some(e),
+        }
+    }
+}
+
+/// Runtime configuration, read from the environment so deployments can
+/// point the service at a different Redis or bind address without
+/// recompiling.
+pub(crate) struct Config {
+    pub(crate) bind: String,
+    pub(crate) port: u16,
+    pub(crate) redis_url: String,
+}
+
+impl Config {
+    pub(crate) fn from_env() -> Result<Config, ConfigError> {
+        let bind = env::var("BIND").unwrap_or_else(|_| DEFAULT_BIND.to_owned());
+
+        let port = match env::var("PORT") {
+            Ok(v) => v.parse::<u16>().map_err(ConfigError::InvalidPort)?,
+            Err(_) => DEFAULT_PORT,
+        };
+
+        let redis_url = env::var("REDIS_URL").unwrap_or_else(|_| DEFAULT_REDIS_URL.to_owned());
+
+        // `Client::open` only parses the URL into a `ConnectionInfo`; it
+        // doesn't connect. Calling it here validates REDIS_URL eagerly so
+        // a typo fails at startup instead of on the first `/acquire`.
+        redis::Client::open(redis_url.as_str()).map_err(ConfigError::InvalidRedisUrl)?;
+
+        Ok(Config {
+            bind,
+            port,
+            redis_url,
+        })
+    }
+
+    pub(crate) fn bind_addr(&self) -> String {
+        format!("{}:{}", self.bind, self.port)
+    }
+}
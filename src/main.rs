@@ -4,7 +4,8 @@ extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate chrono;
-extern crate rand;
+extern crate r2d2;
+extern crate r2d2_redis;
 extern crate redis;
 extern crate tiny_http;
 
@@ -13,13 +14,21 @@ use serde_json::Error as JSONError;
 use std::error::Error as ErrorTrait;
 use std::io::Error as IOError;
 
-use chrono::prelude::*;
-use redis::Commands;
-use std::collections::HashSet;
 use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
 
-struct CustomError {
-    description: String,
+mod config;
+mod store;
+
+use config::{Config, ConfigError};
+use store::{RedisStore, Store};
+
+#[derive(Debug)]
+pub(crate) struct CustomError {
+    pub(crate) description: String,
 }
 
 impl fmt::Display for CustomError {
@@ -28,24 +37,57 @@ impl fmt::Display for CustomError {
     }
 }
 
-enum Error {
+#[derive(Debug)]
+pub(crate) enum Error {
     IO(IOError),
     JSON(JSONError),
     Redis(RedisError),
+    Pool(r2d2::Error),
+    Config(ConfigError),
+    /// The client sent something we can't act on (bad/empty JSON body,
+    /// missing field, invalid parameter). Surfaced as HTTP 400, like
+    /// `JSON`; every other variant here is our fault (500).
+    BadRequest(String),
     Other(CustomError),
 }
 
+impl Error {
+    fn is_client_error(&self) -> bool {
+        match *self {
+            Error::JSON(_) | Error::BadRequest(_) => true,
+            _ => false,
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::IO(ref e) => write!(f, "{}", e),
             Error::JSON(ref e) => write!(f, "{}", e),
             Error::Redis(ref e) => write!(f, "{}", e),
+            Error::Pool(ref e) => write!(f, "{}", e),
+            Error::Config(ref e) => write!(f, "{}", e),
+            Error::BadRequest(ref s) => write!(f, "{}", s),
             Error::Other(ref e) => write!(f, "{}", e),
         }
     }
 }
 
+impl ErrorTrait for Error {
+    fn source(&self) -> Option<&(dyn ErrorTrait + 'static)> {
+        match *self {
+            Error::IO(ref e) => Some(e),
+            Error::JSON(ref e) => Some(e),
+            Error::Redis(ref e) => Some(e),
+            Error::Pool(ref e) => Some(e),
+            Error::Config(ref e) => Some(e),
+            Error::BadRequest(_) => None,
+            Error::Other(_) => None,
+        }
+    }
+}
+
 impl From<JSONError> for Error {
     fn from(err: JSONError) -> Self {
         return Error::JSON(err);
@@ -64,127 +106,24 @@ impl From<RedisError> for Error {
     }
 }
 
-impl From<std::num::ParseIntError> for Error {
-    fn from(err: std::num::ParseIntError) -> Self {
-        return Error::Other(CustomError {
-            description: err.description().to_owned(),
-        });
+impl From<r2d2::Error> for Error {
+    fn from(err: r2d2::Error) -> Self {
+        return Error::Pool(err);
     }
 }
 
-type ProducerID = u16;
-
-#[derive(Debug, Serialize, Deserialize)]
-struct PodHistoryEntry {
-    producer_id: ProducerID,
-    date: DateTime<Utc>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct ProducerHistoryEntry {
-    pod_name: String,
-    date: DateTime<Utc>,
-}
-
-struct Processor {
-    conn: redis::Connection,
+impl From<ConfigError> for Error {
+    fn from(err: ConfigError) -> Self {
+        return Error::Config(err);
+    }
 }
 
-type PodHistory = Vec<PodHistoryEntry>;
-type ProducerHistory = Vec<ProducerHistoryEntry>;
-
-impl Processor {
-    fn new(client: redis::Client) -> Result<Processor, Error> {
-        return Ok(Processor {
-            conn: client.get_connection()?,
+impl From<std::num::ParseIntError> for Error {
+    fn from(err: std::num::ParseIntError) -> Self {
+        return Error::Other(CustomError {
+            description: err.to_string(),
         });
     }
-
-    const REDIS_IDS_KEY: &'static str = "producerid-service::ids";
-
-    fn new_id(all_ids: HashSet<ProducerID>) -> ProducerID {
-        let mut n: u16 = 0;
-        let mut exists: bool = true;
-
-        while n <= 0 || exists {
-            n = rand::random::<u16>();
-            exists = all_ids.contains(&n);
-        }
-
-        n
-    }
-
-    fn mk_pod_key(name: &str) -> String {
-        const K: &'static str = "producerid-service::history_per_pod";
-        format!("{}::{}", K, name)
-    }
-
-    fn mk_producer_key(id: ProducerID) -> String {
-        const K: &'static str = "producerid-service::history_per_producer";
-        format!("{}::{}", K, id)
-    }
-
-    fn pod_history(&mut self, pod_name: &str) -> Result<PodHistory, Error> {
-        self.history(&Processor::mk_pod_key(pod_name))
-    }
-
-    fn producer_history(&mut self, producer_id: ProducerID) -> Result<ProducerHistory, Error> {
-        self.history(&Processor::mk_producer_key(producer_id))
-    }
-
-    fn history<T: serde::de::DeserializeOwned>(&mut self, key: &str) -> Result<Vec<T>, Error> {
-        let vals: Vec<String> = self.conn.lrange(key, 0, -1)?;
-
-        let iter = vals.into_iter();
-        let mapped: Result<Vec<_>, JSONError> = iter.map(|v| serde_json::from_str(&v)).collect();
-
-        // Wut ?
-        match mapped {
-            Ok(v) => Ok(v),
-            Err(e) => Err(Error::JSON(e)),
-        }
-    }
-
-    fn release(&mut self, pod_name: &str) -> Result<(), Error> {
-        self.conn.hdel(Processor::REDIS_IDS_KEY, pod_name)?;
-        Ok(())
-    }
-
-    fn acquire(&mut self, pod_name: &str) -> Result<ProducerID, Error> {
-        let all_ids: HashSet<ProducerID> = self.conn.hvals(Processor::REDIS_IDS_KEY)?;
-
-        let id = self
-            .conn
-            .hget(Processor::REDIS_IDS_KEY, pod_name)
-            .or_else(|_| {
-                let new_id = Processor::new_id(all_ids);
-                Ok(new_id)
-            });
-
-        match id {
-            Ok(v) => {
-                self.conn.hset(Processor::REDIS_IDS_KEY, pod_name, v)?;
-
-                self.conn.lpush(
-                    Processor::mk_pod_key(pod_name),
-                    serde_json::to_string(&PodHistoryEntry {
-                        producer_id: v,
-                        date: Utc::now(),
-                    })?,
-                )?;
-                self.conn.lpush(
-                    Processor::mk_producer_key(v),
-                    serde_json::to_string(&ProducerHistoryEntry {
-                        pod_name: pod_name.to_owned(),
-                        date: Utc::now(),
-                    })?,
-                )?;
-
-                id
-            }
-            _ => id,
-        }
-    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -199,25 +138,30 @@ struct StatusResponse {
     error: Option<String>,
 }
 
-struct Server {
-    http_server: tiny_http::Server,
-    processor: Processor,
+struct Server<S: Store> {
+    http_server: Arc<tiny_http::Server>,
+    store: S,
 }
 
-impl Server {
-    fn new() -> Result<Server, Error> {
-        let http_server = tiny_http::Server::http("0.0.0.0:6070").unwrap();
-        let redis_client = redis::Client::open("redis://127.0.0.1:6379")?;
-        let processor = Processor::new(redis_client)?;
+impl Server<RedisStore> {
+    fn new(config: Config) -> Result<Server<RedisStore>, Error> {
+        let http_server = tiny_http::Server::http(config.bind_addr()).map_err(|e| {
+            Error::Other(CustomError {
+                description: e.to_string(),
+            })
+        })?;
+        let store = RedisStore::new(&config.redis_url)?;
 
         return Ok(Server {
-            http_server: http_server,
-            processor: processor,
+            http_server: Arc::new(http_server),
+            store: store,
         });
     }
+}
 
-    fn process_one(&mut self, mut hreq: tiny_http::Request) -> Result<(), Error> {
-        let response: serde_json::Value = match hreq.url().as_ref() {
+impl<S: Store> Server<S> {
+    fn handle(store: &mut S, hreq: &mut tiny_http::Request) -> Result<serde_json::Value, Error> {
+        match hreq.url().as_ref() {
             "/history/pod" => {
                 #[derive(Deserialize)]
                 struct Request {
@@ -227,11 +171,11 @@ impl Server {
                 let reader = hreq.as_reader();
                 let r: Request = serde_json::from_reader(reader)?;
 
-                let all_ids = self.processor.pod_history(&r.pod_name)?;
+                let all_ids = store.pod_history(&r.pod_name)?;
 
-                json!({
+                Ok(json!({
                     "producer_ids": all_ids,
-                })
+                }))
             }
             "/history/producer" => {
                 #[derive(Deserialize)]
@@ -242,11 +186,11 @@ impl Server {
                 let reader = hreq.as_reader();
                 let r: Request = serde_json::from_reader(reader)?;
 
-                let all_pods = self.processor.producer_history(r.producer_id)?;
+                let all_pods = store.producer_history(r.producer_id)?;
 
-                json!({
+                Ok(json!({
                     "pods": all_pods,
-                })
+                }))
             }
             "/acquire" => {
                 #[derive(Deserialize)]
@@ -256,15 +200,13 @@ impl Server {
 
                 let r: Request = serde_json::from_reader(hreq.as_reader())?;
 
-                match r.pod_name.is_empty() {
-                    true => serde_json::to_value(StatusResponse {
-                        status: Status::ERROR,
-                        error: Some("pod name can't be empty".to_owned()),
-                    })?,
-                    false => json!({
-                        "producer_id": self.processor.acquire(&r.pod_name)?
-                    }),
+                if r.pod_name.is_empty() {
+                    return Err(Error::BadRequest("pod name can't be empty".to_owned()));
                 }
+
+                Ok(json!({
+                    "producer_id": store.acquire(&r.pod_name)?
+                }))
             }
             "/release" => {
                 #[derive(Deserialize)]
@@ -274,44 +216,93 @@ impl Server {
 
                 let r: Request = serde_json::from_reader(hreq.as_reader())?;
 
-                match r.pod_name.is_empty() {
-                    true => serde_json::to_value(StatusResponse {
-                        status: Status::ERROR,
-                        error: Some("pod name can't be empty".to_owned()),
-                    })?,
-                    false => {
-                        self.processor.release(&r.pod_name)?;
-
-                        serde_json::to_value(StatusResponse {
-                            status: Status::OK,
-                            error: None,
-                        })?
-                    }
+                if r.pod_name.is_empty() {
+                    return Err(Error::BadRequest("pod name can't be empty".to_owned()));
                 }
+
+                store.release(&r.pod_name)?;
+
+                Ok(serde_json::to_value(StatusResponse {
+                    status: Status::OK,
+                    error: None,
+                })?)
             }
-            _ => serde_json::to_value(StatusResponse {
+            _ => Ok(serde_json::to_value(StatusResponse {
                 status: Status::OK,
                 error: None,
-            })?,
+            })?),
+        }
+    }
+
+    fn process_one(store: &mut S, mut hreq: tiny_http::Request) -> Result<(), Error> {
+        let (status_code, body) = match Server::<S>::handle(store, &mut hreq) {
+            Ok(body) => (200, body),
+            Err(e) => {
+                let status_code = if e.is_client_error() { 400 } else { 500 };
+                let body = serde_json::to_value(StatusResponse {
+                    status: Status::ERROR,
+                    error: Some(e.to_string()),
+                })?;
+                (status_code, body)
+            }
         };
 
-        hreq.respond(tiny_http::Response::from_string(response.to_string()))?;
+        let response =
+            tiny_http::Response::from_string(body.to_string()).with_status_code(status_code);
+        hreq.respond(response)?;
         Ok(())
     }
+}
 
-    fn run(&mut self) -> Result<(), Error> {
-        loop {
-            let hreq = self.http_server.recv()?;
+impl<S: Store + Clone + Send + 'static> Server<S> {
+    const WORKER_COUNT: usize = 8;
+
+    fn run(self) -> Result<(), Error> {
+        // Every worker runs an infinite loop, so a dead worker's
+        // `JoinHandle::join()` is the only one that will ever return;
+        // waiting on handles sequentially would block forever on a
+        // healthy worker ahead of the one that actually panicked. Have
+        // each worker report in on a shared channel instead, so `run`
+        // wakes up on whichever one dies first.
+        let (died, worker_died) = mpsc::channel::<()>();
+
+        for _ in 0..Server::<S>::WORKER_COUNT {
+            let http_server = Arc::clone(&self.http_server);
+            let mut store = self.store.clone();
+            let died = died.clone();
+
+            thread::spawn(move || {
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| loop {
+                    let hreq = match http_server.recv() {
+                        Ok(hreq) => hreq,
+                        Err(e) => {
+                            eprintln!("error: {}", e);
+                            continue;
+                        }
+                    };
+
+                    if let Err(e) = Server::process_one(&mut store, hreq) {
+                        eprintln!("error: {}", e);
+                    }
+                }));
 
-            if let Err(e) = self.process_one(hreq) {
-                eprintln!("error: {}", e);
-            }
+                // The loop above never returns normally, so reaching
+                // here means it panicked.
+                let _ = died.send(());
+            });
         }
+
+        let _ = worker_died.recv();
+
+        Err(Error::Other(CustomError {
+            description: "a worker thread panicked".to_owned(),
+        }))
     }
 }
 
 fn run() -> Result<(), Error> {
-    let mut server = Server::new()?;
+    let config = Config::from_env()?;
+    let server = Server::new(config)?;
     return server.run();
 }
 
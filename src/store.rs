@@ -0,0 +1,384 @@
+use chrono::prelude::*;
+use r2d2_redis::RedisConnectionManager;
+use redis::Commands;
+use serde_json::Error as JSONError;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use super::{CustomError, Error};
+
+pub(crate) type ProducerID = u16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PodHistoryEntry {
+    producer_id: ProducerID,
+    date: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ProducerHistoryEntry {
+    pod_name: String,
+    date: DateTime<Utc>,
+}
+
+pub(crate) type PodHistory = Vec<PodHistoryEntry>;
+pub(crate) type ProducerHistory = Vec<ProducerHistoryEntry>;
+
+/// Everything a `Server` needs to hand out, look up and reclaim producer
+/// IDs. `RedisStore` is the real, Redis-backed implementation used in
+/// production; `MockStore` is an in-memory stand-in used in tests so the
+/// acquire/release/history logic can be exercised without a running
+/// Redis.
+pub(crate) trait Store {
+    fn acquire(&mut self, pod_name: &str) -> Result<ProducerID, Error>;
+    fn release(&mut self, pod_name: &str) -> Result<(), Error>;
+    fn pod_history(&mut self, pod_name: &str) -> Result<PodHistory, Error>;
+    fn producer_history(&mut self, producer_id: ProducerID) -> Result<ProducerHistory, Error>;
+}
+
+/// Cheaply cloneable: `pool` is itself a handle around a shared
+/// connection pool, so cloning a `RedisStore` just bumps a couple of
+/// reference counts rather than opening a new connection.
+#[derive(Clone)]
+pub(crate) struct RedisStore {
+    pool: r2d2::Pool<RedisConnectionManager>,
+    acquire_script: Arc<redis::Script>,
+    release_script: Arc<redis::Script>,
+}
+
+impl RedisStore {
+    pub(crate) fn new(redis_url: &str) -> Result<RedisStore, Error> {
+        let manager = RedisConnectionManager::new(redis_url)?;
+        let pool = r2d2::Pool::builder().build(manager)?;
+
+        return Ok(RedisStore {
+            pool,
+            acquire_script: Arc::new(redis::Script::new(include_str!("acquire.lua"))),
+            release_script: Arc::new(redis::Script::new(include_str!("release.lua"))),
+        });
+    }
+
+    const REDIS_IDS_KEY: &'static str = "producerid-service::ids";
+    const REDIS_ID_BITMAP_KEY: &'static str = "producerid-service::id_bitmap";
+
+    fn mk_pod_key(name: &str) -> String {
+        const K: &'static str = "producerid-service::history_per_pod";
+        format!("{}::{}", K, name)
+    }
+
+    fn mk_producer_key(id: ProducerID) -> String {
+        const K: &'static str = "producerid-service::history_per_producer";
+        format!("{}::{}", K, id)
+    }
+
+    fn history<T: serde::de::DeserializeOwned>(&self, key: &str) -> Result<Vec<T>, Error> {
+        let mut conn = self.pool.get()?;
+        let vals: Vec<String> = conn.lrange(key, 0, -1)?;
+
+        let iter = vals.into_iter();
+        let mapped: Result<Vec<_>, JSONError> = iter.map(|v| serde_json::from_str(&v)).collect();
+
+        // Wut ?
+        match mapped {
+            Ok(v) => Ok(v),
+            Err(e) => Err(Error::JSON(e)),
+        }
+    }
+}
+
+impl Store for RedisStore {
+    fn pod_history(&mut self, pod_name: &str) -> Result<PodHistory, Error> {
+        self.history(&RedisStore::mk_pod_key(pod_name))
+    }
+
+    fn producer_history(&mut self, producer_id: ProducerID) -> Result<ProducerHistory, Error> {
+        self.history(&RedisStore::mk_producer_key(producer_id))
+    }
+
+    fn release(&mut self, pod_name: &str) -> Result<(), Error> {
+        let mut conn = self.pool.get()?;
+
+        self.release_script
+            .key(RedisStore::REDIS_ID_BITMAP_KEY)
+            .key(RedisStore::REDIS_IDS_KEY)
+            .arg(pod_name)
+            .invoke::<()>(&mut *conn)?;
+
+        Ok(())
+    }
+
+    fn acquire(&mut self, pod_name: &str) -> Result<ProducerID, Error> {
+        let mut conn = self.pool.get()?;
+
+        let result: Result<ProducerID, redis::RedisError> = self
+            .acquire_script
+            .key(RedisStore::REDIS_ID_BITMAP_KEY)
+            .key(RedisStore::REDIS_IDS_KEY)
+            .arg(pod_name)
+            .invoke(&mut *conn);
+
+        let id = match result {
+            Ok(id) => id,
+            Err(ref e)
+                if e.kind() == redis::ErrorKind::ExtensionError && e.code() == Some("NOIDS") =>
+            {
+                return Err(Error::Other(CustomError {
+                    description: "no producer IDs available".to_owned(),
+                }));
+            }
+            Err(e) => return Err(Error::Redis(e)),
+        };
+
+        conn.lpush(
+            RedisStore::mk_pod_key(pod_name),
+            serde_json::to_string(&PodHistoryEntry {
+                producer_id: id,
+                date: Utc::now(),
+            })?,
+        )?;
+        conn.lpush(
+            RedisStore::mk_producer_key(id),
+            serde_json::to_string(&ProducerHistoryEntry {
+                pod_name: pod_name.to_owned(),
+                date: Utc::now(),
+            })?,
+        )?;
+
+        Ok(id)
+    }
+}
+
+/// In-memory `Store` used by tests in place of a live Redis. It mirrors
+/// `RedisStore`'s semantics: the lowest free ID is handed out, history
+/// entries are kept most-recent-first (as `LPUSH`/`LRANGE` would return
+/// them), and exhausting the ID space reports the same `Error::Other` as
+/// the real `acquire.lua` script.
+pub(crate) struct MockStore {
+    ids: HashMap<String, ProducerID>,
+    used: HashSet<ProducerID>,
+    // Lowest ID that might still be free. Only ever moves forward in
+    // `new_id`, and is pulled back down in `release` when a lower ID is
+    // freed, mirroring the `BITPOS`-from-the-start scan `acquire.lua`
+    // does against the real bitmap. Without it, `new_id` would have to
+    // rescan `used` from 1 on every call, which is O(n) per acquire (and
+    // O(n^2) to fill the whole ID space).
+    next_candidate: u32,
+    pod_history: HashMap<String, PodHistory>,
+    producer_history: HashMap<ProducerID, ProducerHistory>,
+}
+
+impl MockStore {
+    pub(crate) fn new() -> MockStore {
+        MockStore {
+            ids: HashMap::new(),
+            used: HashSet::new(),
+            next_candidate: 1,
+            pod_history: HashMap::new(),
+            producer_history: HashMap::new(),
+        }
+    }
+
+    fn new_id(&mut self) -> Result<ProducerID, Error> {
+        while self.next_candidate <= 65535
+            && self.used.contains(&(self.next_candidate as ProducerID))
+        {
+            self.next_candidate += 1;
+        }
+
+        if self.next_candidate > 65535 {
+            return Err(Error::Other(CustomError {
+                description: "no producer IDs available".to_owned(),
+            }));
+        }
+
+        Ok(self.next_candidate as ProducerID)
+    }
+}
+
+impl Store for MockStore {
+    fn acquire(&mut self, pod_name: &str) -> Result<ProducerID, Error> {
+        if let Some(&id) = self.ids.get(pod_name) {
+            return Ok(id);
+        }
+
+        let id = self.new_id()?;
+        self.used.insert(id);
+        self.ids.insert(pod_name.to_owned(), id);
+
+        self.pod_history
+            .entry(pod_name.to_owned())
+            .or_insert_with(Vec::new)
+            .insert(
+                0,
+                PodHistoryEntry {
+                    producer_id: id,
+                    date: Utc::now(),
+                },
+            );
+        self.producer_history
+            .entry(id)
+            .or_insert_with(Vec::new)
+            .insert(
+                0,
+                ProducerHistoryEntry {
+                    pod_name: pod_name.to_owned(),
+                    date: Utc::now(),
+                },
+            );
+
+        Ok(id)
+    }
+
+    fn release(&mut self, pod_name: &str) -> Result<(), Error> {
+        if let Some(id) = self.ids.remove(pod_name) {
+            self.used.remove(&id);
+            self.next_candidate = self.next_candidate.min(u32::from(id));
+        }
+        Ok(())
+    }
+
+    fn pod_history(&mut self, pod_name: &str) -> Result<PodHistory, Error> {
+        Ok(self
+            .pod_history
+            .get(pod_name)
+            .cloned()
+            .unwrap_or_else(Vec::new))
+    }
+
+    fn producer_history(&mut self, producer_id: ProducerID) -> Result<ProducerHistory, Error> {
+        Ok(self
+            .producer_history
+            .get(&producer_id)
+            .cloned()
+            .unwrap_or_else(Vec::new))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn acquire_assigns_lowest_free_id() {
+        let mut store = MockStore::new();
+
+        assert_eq!(store.acquire("pod-a").unwrap(), 1);
+        assert_eq!(store.acquire("pod-b").unwrap(), 2);
+        assert_eq!(store.acquire("pod-c").unwrap(), 3);
+    }
+
+    #[test]
+    fn acquire_is_idempotent_for_the_same_pod() {
+        let mut store = MockStore::new();
+
+        let first = store.acquire("pod-a").unwrap();
+        let second = store.acquire("pod-a").unwrap();
+
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn release_then_reacquire_reuses_the_freed_id() {
+        let mut store = MockStore::new();
+
+        let id = store.acquire("pod-a").unwrap();
+        store.acquire("pod-b").unwrap();
+
+        store.release("pod-a").unwrap();
+
+        let reacquired = store.acquire("pod-c").unwrap();
+        assert_eq!(reacquired, id);
+    }
+
+    #[test]
+    fn two_pods_never_get_the_same_id() {
+        let mut store = MockStore::new();
+
+        let a = store.acquire("pod-a").unwrap();
+        let b = store.acquire("pod-b").unwrap();
+
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn history_is_ordered_most_recent_first() {
+        let mut store = MockStore::new();
+
+        let first_id = store.acquire("pod-a").unwrap();
+        store.release("pod-a").unwrap();
+        // pod-b takes the freed ID, so pod-a gets a different one next.
+        store.acquire("pod-b").unwrap();
+        let second_id = store.acquire("pod-a").unwrap();
+        assert_ne!(first_id, second_id);
+
+        let pod_history = store.pod_history("pod-a").unwrap();
+        assert_eq!(pod_history.len(), 2);
+        assert_eq!(pod_history[0].producer_id, second_id);
+        assert_eq!(pod_history[1].producer_id, first_id);
+
+        let producer_history = store.producer_history(first_id).unwrap();
+        assert_eq!(producer_history.len(), 2);
+        assert_eq!(producer_history[0].pod_name, "pod-b");
+        assert_eq!(producer_history[1].pod_name, "pod-a");
+    }
+
+    #[test]
+    fn acquire_fails_once_the_id_space_is_exhausted() {
+        let mut store = MockStore::new();
+
+        // Seed the store straight into an "every ID taken" state instead
+        // of looping 65535 real `acquire()` calls through it - this test
+        // only cares about the exhaustion path, not about how we got
+        // there, and the loop form makes `cargo test` noticeably slower
+        // for no benefit.
+        store.used = (1..=65535u32).map(|id| id as ProducerID).collect();
+        store.next_candidate = 65536;
+
+        match store.acquire("one-too-many") {
+            Err(Error::Other(_)) => {}
+            Ok(_) => panic!("expected the ID space to be exhausted"),
+            Err(_) => panic!("expected Error::Other, got a different Error variant"),
+        }
+    }
+
+    // This only checks that acquiring from several threads at once doesn't
+    // panic or corrupt `MockStore`'s bookkeeping; it's not a regression
+    // test for the race chunk0-1 fixed. The lock is held for the whole
+    // `acquire()` call, so there's never any real interleaving inside
+    // `new_id`/`release` for this test to catch - it would pass just as
+    // well wrapped around the old, pre-chunk0-1 logic. The actual
+    // atomicity guarantee against concurrent acquires lives in
+    // `acquire.lua`/`release.lua`, which only `RedisStore` exercises.
+    #[test]
+    fn acquire_from_multiple_threads_yields_distinct_ids() {
+        let store = Arc::new(Mutex::new(MockStore::new()));
+        const POD_COUNT: usize = 32;
+
+        let handles: Vec<_> = (0..POD_COUNT)
+            .map(|n| {
+                let store = Arc::clone(&store);
+
+                thread::spawn(move || {
+                    store
+                        .lock()
+                        .unwrap()
+                        .acquire(&format!("pod-{}", n))
+                        .unwrap()
+                })
+            })
+            .collect();
+
+        let ids: Vec<ProducerID> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        let unique: HashSet<ProducerID> = ids.iter().cloned().collect();
+        assert_eq!(
+            unique.len(),
+            POD_COUNT,
+            "expected {} distinct IDs, got {:?}",
+            POD_COUNT,
+            ids
+        );
+    }
+}